@@ -1,11 +1,14 @@
 use js_sys::Promise;
+use std::cell::{Cell, RefCell};
 use std::future::Future;
+use std::rc::Rc;
+use url::Url;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::{future_to_promise, JsFuture};
 
 use rustpython_vm::common::rc::PyRc;
-use rustpython_vm::function::{OptionalArg, PyFuncArgs};
+use rustpython_vm::function::{OptionalArg, PyFuncArgs, PyIterable};
 use rustpython_vm::import::import_file;
 use rustpython_vm::obj::{objdict::PyDictRef, objstr::PyStrRef, objtype::PyTypeRef};
 use rustpython_vm::pyobject::{
@@ -16,6 +19,12 @@ use rustpython_vm::VirtualMachine;
 
 use crate::{convert, vm_class::weak_vm, wasm_builtins::window};
 
+#[derive(Debug, Clone, Copy)]
+enum PromiseResolution {
+    Value,
+    Response,
+}
+
 enum FetchResponseFormat {
     Json,
     Text,
@@ -52,6 +61,67 @@ struct FetchArgs {
     body: Option<PyObjectRef>,
     #[pyarg(named, default)]
     content_type: Option<PyStrRef>,
+    #[pyarg(named, default = "false")]
+    as_response: bool,
+    #[pyarg(named, default)]
+    timeout: Option<f64>,
+}
+
+thread_local! {
+    static BASE_URL: RefCell<Option<String>> = RefCell::new(None);
+    static UPGRADE_TO_HTTPS: Cell<bool> = Cell::new(false);
+}
+
+fn browser_set_base_url(
+    url: OptionalArg<PyStrRef>,
+    upgrade_to_https: OptionalArg<bool>,
+    _vm: &VirtualMachine,
+) -> PyResult<()> {
+    BASE_URL.with(|base| {
+        *base.borrow_mut() = url.into_option().map(|s| s.borrow_value().to_owned());
+    });
+    if let OptionalArg::Present(upgrade) = upgrade_to_https {
+        UPGRADE_TO_HTTPS.with(|flag| flag.set(upgrade));
+    }
+    Ok(())
+}
+
+/// Resolves `raw` against the configured base URL (or the document's
+/// `base_uri` if none was set), upgrading it from `http:` to `https:` when
+/// `set_base_url`'s `upgrade_to_https` flag is set and the current page is
+/// itself served over https.
+fn resolve_url(vm: &VirtualMachine, raw: &str) -> PyResult<String> {
+    let base = BASE_URL.with(|base| base.borrow().clone());
+    let base = match base {
+        Some(base) => base,
+        None => window()
+            .document()
+            .expect("Document missing from window")
+            .base_uri()
+            .map_err(|err| convert::js_py_typeerror(vm, err))?
+            .ok_or_else(|| {
+                vm.new_value_error("document has no base URI to resolve the URL against".to_owned())
+            })?,
+    };
+
+    let base = Url::parse(&base).map_err(|err| vm.new_value_error(err.to_string()))?;
+    let mut resolved = base
+        .join(raw)
+        .map_err(|err| vm.new_value_error(err.to_string()))?;
+
+    let upgrade = UPGRADE_TO_HTTPS.with(|flag| flag.get());
+    let page_is_https = window()
+        .location()
+        .protocol()
+        .map_or(false, |protocol| protocol == "https:");
+
+    if upgrade && page_is_https && resolved.scheme() == "http" {
+        resolved
+            .set_scheme("https")
+            .expect("http to https is always a valid scheme change");
+    }
+
+    Ok(resolved.to_string())
 }
 
 fn browser_fetch(url: PyStrRef, args: FetchArgs, vm: &VirtualMachine) -> PyResult {
@@ -61,6 +131,8 @@ fn browser_fetch(url: PyStrRef, args: FetchArgs, vm: &VirtualMachine) -> PyResul
         headers,
         body,
         content_type,
+        as_response,
+        timeout,
     } = args;
 
     let response_format = match response_format {
@@ -79,7 +151,13 @@ fn browser_fetch(url: PyStrRef, args: FetchArgs, vm: &VirtualMachine) -> PyResul
         opts.body(Some(&convert::py_to_js(vm, body)));
     }
 
-    let request = web_sys::Request::new_with_str_and_init(url.borrow_value(), &opts)
+    let url = resolve_url(vm, url.borrow_value())?;
+
+    let controller =
+        web_sys::AbortController::new().map_err(|err| convert::js_py_typeerror(vm, err))?;
+    opts.signal(Some(&controller.signal()));
+
+    let request = web_sys::Request::new_with_str_and_init(&url, &opts)
         .map_err(|err| convert::js_py_typeerror(vm, err))?;
 
     if let Some(headers) = headers {
@@ -99,18 +177,162 @@ fn browser_fetch(url: PyStrRef, args: FetchArgs, vm: &VirtualMachine) -> PyResul
             .map_err(|err| convert::js_py_typeerror(vm, err))?;
     }
 
+    let timed_out = Rc::new(Cell::new(false));
+    // Holds the timeout's Closure so we can drop it ourselves once the
+    // request settles, instead of leaking it via `Closure::once_into_js`
+    // for every timed fetch that completes before its deadline fires.
+    let on_timeout_closure: Rc<RefCell<Option<Closure<dyn FnMut()>>>> = Rc::new(RefCell::new(None));
+
+    let timeout_id = if let Some(timeout) = timeout {
+        let abort_controller = controller.clone();
+        let timed_out = timed_out.clone();
+        let closure_slot = on_timeout_closure.clone();
+        let on_timeout = Closure::wrap(Box::new(move || {
+            timed_out.set(true);
+            abort_controller.abort();
+            closure_slot.borrow_mut().take();
+        }) as Box<dyn FnMut()>);
+        let id = window()
+            .set_timeout_with_callback_and_timeout_and_arguments_0(
+                on_timeout.as_ref().unchecked_ref(),
+                (timeout * 1000.0) as i32,
+            )
+            .map_err(|err| convert::js_py_typeerror(vm, err))?;
+        *on_timeout_closure.borrow_mut() = Some(on_timeout);
+        Some(id)
+    } else {
+        None
+    };
+
     let window = window();
     let request_prom = window.fetch_with_request(&request);
+    let weak_vm = weak_vm(vm);
 
     let future = async move {
-        let val = JsFuture::from(request_prom).await?;
+        let request_result = JsFuture::from(request_prom).await;
+        // The request settled one way or another; if a timeout was still
+        // pending, it no longer needs to fire, so drop its closure too.
+        if let Some(id) = timeout_id {
+            window.clear_timeout_with_handle(id);
+            on_timeout_closure.borrow_mut().take();
+        }
+        let val = match request_result {
+            Ok(val) => val,
+            Err(err) => {
+                let is_abort = err
+                    .dyn_ref::<web_sys::DomException>()
+                    .map_or(false, |err| err.name() == "AbortError");
+                return if is_abort {
+                    let stored_vm = weak_vm
+                        .upgrade()
+                        .expect("that the vm is valid when the request aborts");
+                    stored_vm.interp.enter(|vm| {
+                        let exc = if timed_out.get() {
+                            vm.new_exception_msg(
+                                vm.ctx.exceptions.timeout_error.clone(),
+                                "fetch timed out".to_owned(),
+                            )
+                        } else {
+                            vm.new_runtime_error("fetch was canceled".to_owned())
+                        };
+                        Err(convert::py_err_to_js_err(vm, &exc))
+                    })
+                } else {
+                    Err(err)
+                };
+            }
+        };
         let response = val
             .dyn_into::<web_sys::Response>()
             .expect("val to be of type Response");
-        JsFuture::from(response_format.get_response(&response)?).await
+        if as_response {
+            Ok(response.into())
+        } else {
+            JsFuture::from(response_format.get_response(&response)?).await
+        }
     };
 
-    Ok(PyPromise::from_future(future).into_object(vm))
+    let promise = if as_response {
+        PyPromise::from_response_future(future)
+    } else {
+        PyPromise::from_future(future)
+    };
+
+    Ok(promise.with_abort(controller).into_object(vm))
+}
+
+#[derive(FromArgs)]
+struct NavigateArgs {
+    #[pyarg(named, default)]
+    method: Option<PyStrRef>,
+    #[pyarg(named, default)]
+    vars: Option<PyDictRef>,
+    #[pyarg(named, default)]
+    target: Option<PyStrRef>,
+}
+
+fn browser_navigate(url: PyStrRef, args: NavigateArgs, vm: &VirtualMachine) -> PyResult<()> {
+    let NavigateArgs {
+        method,
+        vars,
+        target,
+    } = args;
+
+    let window = window();
+    let document = window.document().expect("Document missing from window");
+
+    match vars {
+        Some(vars) => {
+            let form = document
+                .create_element("form")
+                .map_err(|err| convert::js_py_typeerror(vm, err))?
+                .dyn_into::<web_sys::HtmlFormElement>()
+                .expect("created element to be a HtmlFormElement");
+
+            form.set_method(method.as_ref().map_or("get", |s| s.borrow_value()));
+            form.set_action(url.borrow_value());
+            if let Some(target) = &target {
+                form.set_target(target.borrow_value());
+            }
+
+            for (key, value) in vars {
+                let key = vm.to_str(&key)?;
+                let value = vm.to_str(&value)?;
+
+                let input = document
+                    .create_element("input")
+                    .map_err(|err| convert::js_py_typeerror(vm, err))?;
+                input
+                    .set_attribute("type", "hidden")
+                    .map_err(|err| convert::js_py_typeerror(vm, err))?;
+                input
+                    .set_attribute("name", key.borrow_value())
+                    .map_err(|err| convert::js_py_typeerror(vm, err))?;
+                input
+                    .set_attribute("value", value.borrow_value())
+                    .map_err(|err| convert::js_py_typeerror(vm, err))?;
+                form.append_child(&input)
+                    .map_err(|err| convert::js_py_typeerror(vm, err))?;
+            }
+
+            let body = document.body().expect("Document missing a body");
+            body.append_child(&form)
+                .map_err(|err| convert::js_py_typeerror(vm, err))?;
+            form.submit().map_err(|err| convert::js_py_typeerror(vm, err))?;
+            body.remove_child(&form)
+                .map_err(|err| convert::js_py_typeerror(vm, err))?;
+        }
+        None => {
+            match target {
+                Some(target) => window
+                    .open_with_url_and_target(url.borrow_value(), target.borrow_value()),
+                None => window.open_with_url(url.borrow_value()),
+            }
+            .map_err(|err| convert::js_py_typeerror(vm, err))?;
+        }
+    }
+
+    Ok(())
 }
 
 fn browser_request_animation_frame(func: PyCallable, vm: &VirtualMachine) -> PyResult {
@@ -159,6 +381,8 @@ fn browser_cancel_animation_frame(id: i32, vm: &VirtualMachine) -> PyResult<()>
 #[derive(Debug)]
 pub struct PyPromise {
     value: Promise,
+    resolution: PromiseResolution,
+    abort: Option<web_sys::AbortController>,
 }
 pub type PyPromiseRef = PyRef<PyPromise>;
 
@@ -171,7 +395,11 @@ impl PyValue for PyPromise {
 #[pyimpl]
 impl PyPromise {
     pub fn new(value: Promise) -> PyPromise {
-        PyPromise { value }
+        PyPromise {
+            value,
+            resolution: PromiseResolution::Value,
+            abort: None,
+        }
     }
     pub fn from_future<F>(future: F) -> PyPromise
     where
@@ -179,10 +407,40 @@ impl PyPromise {
     {
         PyPromise::new(future_to_promise(future))
     }
+    /// Like `from_future`, but the value the future resolves to is treated as
+    /// a `web_sys::Response` and converted to a `browser.Response` instead of
+    /// being passed through the generic JS->Python conversion.
+    pub fn from_response_future<F>(future: F) -> PyPromise
+    where
+        F: Future<Output = Result<JsValue, JsValue>> + 'static,
+    {
+        PyPromise {
+            value: future_to_promise(future),
+            resolution: PromiseResolution::Response,
+            abort: None,
+        }
+    }
+    /// Attaches a cancel handle so Python can abort the in-flight request
+    /// backing this promise via `cancel()`.
+    pub fn with_abort(mut self, abort: web_sys::AbortController) -> PyPromise {
+        self.abort = Some(abort);
+        self
+    }
     pub fn value(&self) -> Promise {
         self.value.clone()
     }
 
+    #[pymethod]
+    fn cancel(&self, vm: &VirtualMachine) -> PyResult<()> {
+        match &self.abort {
+            Some(controller) => {
+                controller.abort();
+                Ok(())
+            }
+            None => Err(vm.new_value_error("this promise can't be canceled".to_owned())),
+        }
+    }
+
     #[pymethod]
     fn then(
         &self,
@@ -192,6 +450,7 @@ impl PyPromise {
     ) -> PyPromiseRef {
         let weak_vm = weak_vm(vm);
         let prom = JsFuture::from(self.value.clone());
+        let resolution = self.resolution;
 
         let ret_future = async move {
             let stored_vm = &weak_vm
@@ -203,7 +462,16 @@ impl PyPromise {
                     let args = if val.is_null() {
                         vec![]
                     } else {
-                        vec![convert::js_to_py(vm, val)]
+                        let obj = match resolution {
+                            PromiseResolution::Value => convert::js_to_py(vm, val),
+                            PromiseResolution::Response => Response {
+                                resp: val
+                                    .dyn_into()
+                                    .expect("resolved value to be a web_sys::Response"),
+                            }
+                            .into_pyobject(vm),
+                        };
+                        vec![obj]
                     };
                     let res = vm.invoke(&on_fulfill.into_object(), PyFuncArgs::new(args, vec![]));
                     convert::pyresult_to_jsresult(vm, res)
@@ -250,6 +518,44 @@ impl PyPromise {
 
         PyPromise::from_future(ret_future).into_ref(vm)
     }
+
+    #[pyclassmethod]
+    fn resolve(_cls: PyTypeRef, value: PyObjectRef, vm: &VirtualMachine) -> PyPromiseRef {
+        let value = convert::py_to_js(vm, value);
+        PyPromise::new(Promise::resolve(&value)).into_ref(vm)
+    }
+
+    #[pyclassmethod]
+    fn reject(_cls: PyTypeRef, value: PyObjectRef, vm: &VirtualMachine) -> PyPromiseRef {
+        let value = convert::py_to_js(vm, value);
+        PyPromise::new(Promise::reject(&value)).into_ref(vm)
+    }
+
+    #[pyclassmethod]
+    fn all(
+        _cls: PyTypeRef,
+        iterable: PyIterable<PyPromiseRef>,
+        vm: &VirtualMachine,
+    ) -> PyResult<PyPromiseRef> {
+        let array = js_sys::Array::new();
+        for promise in iterable.iter(vm)? {
+            array.push(&promise?.value());
+        }
+        Ok(PyPromise::new(Promise::all(&array)).into_ref(vm))
+    }
+
+    #[pyclassmethod]
+    fn race(
+        _cls: PyTypeRef,
+        iterable: PyIterable<PyPromiseRef>,
+        vm: &VirtualMachine,
+    ) -> PyResult<PyPromiseRef> {
+        let array = js_sys::Array::new();
+        for promise in iterable.iter(vm)? {
+            array.push(&promise?.value());
+        }
+        Ok(PyPromise::new(Promise::race(&array)).into_ref(vm))
+    }
 }
 
 #[pyclass(module = "browser", name)]
@@ -330,13 +636,91 @@ impl Element {
     }
 }
 
+#[pyclass(module = "browser", name = "Response")]
+#[derive(Debug)]
+struct Response {
+    resp: web_sys::Response,
+}
+
+impl PyValue for Response {
+    fn class(vm: &VirtualMachine) -> PyTypeRef {
+        vm.class("browser", "Response")
+    }
+}
+
+#[pyimpl]
+impl Response {
+    #[pyproperty]
+    fn status(&self) -> u16 {
+        self.resp.status()
+    }
+
+    #[pyproperty]
+    fn status_text(&self) -> String {
+        self.resp.status_text()
+    }
+
+    #[pyproperty]
+    fn ok(&self) -> bool {
+        self.resp.ok()
+    }
+
+    #[pymethod]
+    fn headers(&self, vm: &VirtualMachine) -> PyResult<PyDictRef> {
+        let dict = vm.ctx.new_dict();
+        let iter = js_sys::try_iter(&self.resp.headers())
+            .map_err(|err| convert::js_py_typeerror(vm, err))?
+            .expect("Headers to be iterable");
+        for entry in iter {
+            let entry = entry.map_err(|err| convert::js_py_typeerror(vm, err))?;
+            let pair: js_sys::Array = entry.dyn_into().expect("header entry to be an array");
+            let key = pair.get(0).as_string().expect("header name to be a string");
+            let value = pair
+                .get(1)
+                .as_string()
+                .expect("header value to be a string");
+            dict.set_item(&key, vm.ctx.new_str(value), vm)?;
+        }
+        Ok(dict)
+    }
+
+    #[pymethod]
+    fn json(&self, vm: &VirtualMachine) -> PyResult<PyPromiseRef> {
+        self.body_promise(FetchResponseFormat::Json, vm)
+    }
+
+    #[pymethod]
+    fn text(&self, vm: &VirtualMachine) -> PyResult<PyPromiseRef> {
+        self.body_promise(FetchResponseFormat::Text, vm)
+    }
+
+    #[pymethod]
+    fn array_buffer(&self, vm: &VirtualMachine) -> PyResult<PyPromiseRef> {
+        self.body_promise(FetchResponseFormat::ArrayBuffer, vm)
+    }
+
+    fn body_promise(
+        &self,
+        format: FetchResponseFormat,
+        vm: &VirtualMachine,
+    ) -> PyResult<PyPromiseRef> {
+        let prom = format
+            .get_response(&self.resp)
+            .map_err(|err| convert::js_py_typeerror(vm, err))?;
+        let future = async move { JsFuture::from(prom).await };
+        Ok(PyPromise::from_future(future).into_ref(vm))
+    }
+}
+
 fn browser_load_module(module: PyStrRef, path: PyStrRef, vm: &VirtualMachine) -> PyResult {
     let weak_vm = weak_vm(vm);
 
     let mut opts = web_sys::RequestInit::new();
     opts.method("GET");
 
-    let request = web_sys::Request::new_with_str_and_init(path.borrow_value(), &opts)
+    let path = resolve_url(vm, path.borrow_value())?;
+
+    let request = web_sys::Request::new_with_str_and_init(&path, &opts)
         .map_err(|err| convert::js_py_typeerror(vm, err))?;
 
     let window = window();
@@ -381,14 +765,19 @@ pub fn make_module(vm: &VirtualMachine) -> PyObjectRef {
 
     let element = Element::make_class(ctx);
 
+    let response = Response::make_class(ctx);
+
     py_module!(vm, "browser", {
         "fetch" => ctx.new_function(browser_fetch),
+        "navigate" => ctx.new_function(browser_navigate),
+        "set_base_url" => ctx.new_function(browser_set_base_url),
         "request_animation_frame" => ctx.new_function(browser_request_animation_frame),
         "cancel_animation_frame" => ctx.new_function(browser_cancel_animation_frame),
         "Promise" => promise,
         "Document" => document_class,
         "document" => document,
         "Element" => element,
+        "Response" => response,
         "load_module" => ctx.new_function(browser_load_module),
     })
 }